@@ -0,0 +1,51 @@
+//! `edgekv doctor [--dry-run] <dir>`: checks and repairs the data and hint
+//! files in a store directory and prints what it found. Exits with 1 if
+//! anything is left that could not be repaired.
+
+use edgekv::storage::FsStorage;
+use std::path::Path;
+use std::process;
+
+const USAGE: &str = "usage: edgekv doctor [--dry-run] <dir>";
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let (dry_run, dir) = match args.as_slice() {
+        ["doctor", dir] => (false, *dir),
+        ["doctor", "--dry-run", dir] => (true, *dir),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+    // FsStorage creates its directory, which a typo shouldn't do here.
+    if !Path::new(dir).is_dir() {
+        eprintln!("edgekv: {} is not a directory", dir);
+        process::exit(2);
+    }
+
+    let report = match FsStorage::new(dir).and_then(|storage| edgekv::doctor(&storage, dry_run)) {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("edgekv: {}", e);
+            process::exit(1);
+        }
+    };
+    let verb = if dry_run { "would repair" } else { "repaired" };
+    for name in &report.files_repaired {
+        println!("{} {}", verb, name);
+    }
+    for record in &report.corrupt_records {
+        println!("corrupt record in {} at offset {}: {}", record.file, record.offset, record.reason);
+    }
+    println!(
+        "{} records skipped, {} bytes truncated, {} corrupt records left in place",
+        report.records_skipped,
+        report.bytes_truncated,
+        report.corrupt_records.len()
+    );
+    if !report.corrupt_records.is_empty() {
+        process::exit(1);
+    }
+}
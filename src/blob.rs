@@ -0,0 +1,172 @@
+//! Blob files for large values, WiscKey-style. A value above the threshold
+//! is appended to a blob file and its data entry stores only a
+//! [`BlobRef`], flagged as one in the record header, so merging data files
+//! copies a few dozen bytes instead of the value, and the keydir stays the
+//! same size whatever the values are.
+//!
+//! A blob file is a [`FileHeader`] followed by records of
+//! `varint key_size | varint value_size | key | value`. The key is kept so
+//! a later pass can tell which blobs are still referenced.
+
+use crate::error::{EdgeKvError, Result};
+use crate::schema::{crc_checksum, decode_varint, encode_varint, DataEntry, FileHeader, FileKind};
+use crate::storage::StorageFile;
+use std::io::Read;
+
+#[allow(dead_code)]
+pub(crate) fn blob_file_name(file_id: u64) -> String {
+    format!("{:06}.blob", file_id)
+}
+
+/// Where a value lives in a blob file, and the crc of its bytes.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) struct BlobRef {
+    pub(crate) file_id: u64,
+    pub(crate) offset: u64,
+    pub(crate) len: u64,
+    pub(crate) crc: u32,
+}
+
+impl BlobRef {
+    /// `varint file_id | varint offset | varint len | u32 crc`, stored as
+    /// the value of the data entry.
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        encode_varint(self.file_id, &mut buf);
+        encode_varint(self.offset, &mut buf);
+        encode_varint(self.len, &mut buf);
+        buf.extend_from_slice(&self.crc.to_be_bytes());
+        buf
+    }
+
+    /// The data entry that stands in for the value under `key`.
+    #[allow(dead_code)]
+    pub(crate) fn into_entry(self, level: i64, key: Vec<u8>) -> DataEntry {
+        DataEntry::new_blob_ref(level, key, self.encode())
+    }
+
+    /// The reference stored in `entry`, or `None` if its value is inline.
+    #[allow(dead_code)]
+    pub(crate) fn from_entry(entry: &DataEntry) -> Result<Option<Self>> {
+        if !entry.is_blob_ref() {
+            return Ok(None);
+        }
+        Self::decode(entry.value()).map(Some)
+    }
+
+    pub(crate) fn decode(mut raw: &[u8]) -> Result<Self> {
+        let rdr = &mut raw;
+        let file_id = decode_varint(rdr)?;
+        let offset = decode_varint(rdr)?;
+        let len = decode_varint(rdr)?;
+        let mut crc = [0_u8; 4];
+        rdr.read_exact(&mut crc)?;
+        if !rdr.is_empty() {
+            return Err(EdgeKvError::corruption("trailing bytes in blob reference"));
+        }
+        Ok(Self {
+            file_id,
+            offset,
+            len,
+            crc: u32::from_be_bytes(crc),
+        })
+    }
+}
+
+/// Appends values to one blob file.
+#[allow(dead_code)]
+pub(crate) struct BlobWriter<F: StorageFile> {
+    file_id: u64,
+    file: F,
+}
+
+#[allow(dead_code)]
+impl<F: StorageFile> BlobWriter<F> {
+    /// Writes the file header if `file` is new.
+    pub(crate) fn new(file_id: u64, mut file: F) -> Result<Self> {
+        if file.is_empty()? {
+            file.append(&FileHeader::new(FileKind::Blob).encode())?;
+        }
+        Ok(Self { file_id, file })
+    }
+
+    pub(crate) fn append(&mut self, key: &[u8], value: &[u8]) -> Result<BlobRef> {
+        let mut buf = vec![];
+        encode_varint(key.len() as u64, &mut buf);
+        encode_varint(value.len() as u64, &mut buf);
+        buf.extend_from_slice(key);
+        let value_start = buf.len() as u64;
+        buf.extend_from_slice(value);
+        let position = self.file.append(&buf)?;
+        Ok(BlobRef {
+            file_id: self.file_id,
+            offset: position + value_start,
+            len: value.len() as u64,
+            crc: crc_checksum(value),
+        })
+    }
+
+    /// Blobs must be synced before the data entries that refer to them.
+    pub(crate) fn sync(&self) -> Result<()> {
+        self.file.sync()
+    }
+}
+
+/// Reads the value `blob` points at out of its file, checking the crc.
+#[allow(dead_code)]
+pub(crate) fn read_blob<F: StorageFile>(file: &F, blob: &BlobRef) -> Result<Vec<u8>> {
+    let len = file.len()?;
+    if blob.offset.checked_add(blob.len).is_none_or(|end| end > len) {
+        return Err(EdgeKvError::corruption_at(blob.offset, "blob reference past end of blob file"));
+    }
+    let mut value = vec![0_u8; blob.len as usize];
+    file.read_at(blob.offset, &mut value)?;
+    if crc_checksum(&value) != blob.crc {
+        return Err(EdgeKvError::corruption_at(blob.offset, "blob checksum mismatch"));
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blob::{blob_file_name, read_blob, BlobRef, BlobWriter};
+    use crate::schema::{DataEntry, Decoder, Encoder, FormatVersion, SizeLimits};
+    use crate::storage::{MemStorage, Storage, StorageFile};
+    use std::io::Cursor;
+
+    #[test]
+    fn blob_round_trip_test() {
+        let storage = MemStorage::new();
+        let mut writer = BlobWriter::new(3, storage.open(&blob_file_name(3)).unwrap()).unwrap();
+        let big = vec![7_u8; 200_000];
+        let first = writer.append(b"a", &big).unwrap();
+        let second = writer.append(b"b", b"small").unwrap();
+        writer.sync().unwrap();
+
+        let encoded = first.encode();
+        // Three varints of at most ten bytes, and the crc.
+        assert!(encoded.len() <= 3 * 10 + 4);
+        assert_eq!(BlobRef::decode(&encoded).unwrap(), first);
+        assert!(BlobRef::decode(&encoded[..encoded.len() - 1]).is_err());
+
+        let mut file = storage.open("000003.blob").unwrap();
+        assert_eq!(read_blob(&file, &first).unwrap(), big);
+        assert_eq!(read_blob(&file, &second).unwrap(), b"small");
+
+        let entry = first.into_entry(0, b"a".to_vec());
+        let encoded_entry = entry.encode_with_limits(FormatVersion::CURRENT, &SizeLimits::default()).unwrap();
+        let decoded = DataEntry::decode(&mut Cursor::new(encoded_entry)).unwrap();
+        assert!(decoded.check_crc() && decoded.is_blob_ref());
+        assert_eq!(BlobRef::from_entry(&decoded).unwrap(), Some(first));
+        let inline = DataEntry::new(0, b"a".to_vec(), first.encode());
+        let decoded = DataEntry::decode(&mut Cursor::new(inline.encode())).unwrap();
+        assert_eq!(BlobRef::from_entry(&decoded).unwrap(), None);
+        assert!(entry.encode_with_limits(FormatVersion::V1, &SizeLimits::default()).is_err());
+
+        let past_end = BlobRef { len: 10, ..second };
+        assert!(read_blob(&file, &past_end).is_err());
+        file.truncate(second.offset).unwrap();
+        file.append(b"smell").unwrap();
+        assert!(read_blob(&file, &second).is_err());
+    }
+}
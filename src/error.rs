@@ -0,0 +1,171 @@
+//! The error type returned throughout the crate.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::string::FromUtf8Error;
+
+pub type Result<T> = std::result::Result<T, EdgeKvError>;
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum EdgeKvError {
+    /// The underlying storage failed.
+    Io(io::Error),
+    /// Stored data failed validation. `file` and `offset` locate the bad
+    /// record when the failing code knows them.
+    Corruption {
+        file: Option<String>,
+        offset: Option<u64>,
+        reason: String,
+    },
+    KeyTooLarge { size: u64, limit: u64 },
+    ValueTooLarge { size: u64, limit: u64 },
+    /// A file was written in a format version this build cannot read.
+    InvalidFormatVersion(u16),
+    /// A key handed to [`KeyDecoder`](crate::keys::KeyDecoder) was not
+    /// built by [`KeyEncoder`](crate::keys::KeyEncoder), or has fewer
+    /// components than were read.
+    InvalidKey(String),
+}
+
+impl EdgeKvError {
+    pub(crate) fn corruption<S: Into<String>>(reason: S) -> Self {
+        EdgeKvError::Corruption {
+            file: None,
+            offset: None,
+            reason: reason.into(),
+        }
+    }
+
+    pub(crate) fn corruption_at<S: Into<String>>(offset: u64, reason: S) -> Self {
+        EdgeKvError::Corruption {
+            file: None,
+            offset: Some(offset),
+            reason: reason.into(),
+        }
+    }
+
+    /// Names the file a corruption error was found in, unless it already
+    /// names one.
+    pub(crate) fn in_file(self, name: &str) -> Self {
+        match self {
+            EdgeKvError::Corruption { file: None, offset, reason } => EdgeKvError::Corruption {
+                file: Some(name.to_owned()),
+                offset,
+                reason,
+            },
+            e => e,
+        }
+    }
+
+    /// Locates an error raised while decoding the record at `offset`. A
+    /// stored size over the limits can only be corruption, so it becomes
+    /// one. I/O errors pass through unchanged, keeping a torn tail
+    /// recognisable as `UnexpectedEof`.
+    pub(crate) fn at_offset(self, offset: u64) -> Self {
+        match self {
+            EdgeKvError::Corruption { file, offset: None, reason } => EdgeKvError::Corruption {
+                file,
+                offset: Some(offset),
+                reason,
+            },
+            e @ EdgeKvError::KeyTooLarge { .. } | e @ EdgeKvError::ValueTooLarge { .. } => {
+                EdgeKvError::corruption_at(offset, e.to_string())
+            }
+            e => e,
+        }
+    }
+}
+
+impl fmt::Display for EdgeKvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EdgeKvError::Io(e) => write!(f, "i/o error: {}", e),
+            EdgeKvError::Corruption { file, offset, reason } => {
+                write!(f, "corruption")?;
+                if let Some(file) = file {
+                    write!(f, " in {}", file)?;
+                }
+                if let Some(offset) = offset {
+                    write!(f, " at offset {}", offset)?;
+                }
+                write!(f, ": {}", reason)
+            }
+            EdgeKvError::KeyTooLarge { size, limit } => {
+                write!(f, "key size {} exceeds limit of {} bytes", size, limit)
+            }
+            EdgeKvError::ValueTooLarge { size, limit } => {
+                write!(f, "value size {} exceeds limit of {} bytes", size, limit)
+            }
+            EdgeKvError::InvalidFormatVersion(version) => {
+                write!(f, "unsupported format version {}", version)
+            }
+            EdgeKvError::InvalidKey(reason) => write!(f, "invalid key: {}", reason),
+        }
+    }
+}
+
+impl Error for EdgeKvError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            EdgeKvError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for EdgeKvError {
+    fn from(e: io::Error) -> Self {
+        EdgeKvError::Io(e)
+    }
+}
+
+impl From<FromUtf8Error> for EdgeKvError {
+    fn from(e: FromUtf8Error) -> Self {
+        EdgeKvError::InvalidKey(e.to_string())
+    }
+}
+
+/// Lets crate errors pass through `std::io::Read`/`Write` adapters. An I/O
+/// error comes back out unchanged.
+impl From<EdgeKvError> for io::Error {
+    fn from(e: EdgeKvError) -> Self {
+        match e {
+            EdgeKvError::Io(e) => e,
+            e => io::Error::other(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::EdgeKvError;
+    use std::io;
+
+    #[test]
+    fn display_and_io_round_trip_test() {
+        let e = EdgeKvError::Corruption {
+            file: Some("000001.data".into()),
+            offset: Some(42),
+            reason: "checksum mismatch in record".into(),
+        };
+        assert_eq!(e.to_string(), "corruption in 000001.data at offset 42: checksum mismatch in record");
+
+        let io_err: io::Error = EdgeKvError::Io(io::Error::from(io::ErrorKind::UnexpectedEof)).into();
+        assert_eq!(io_err.kind(), io::ErrorKind::UnexpectedEof);
+        match EdgeKvError::from(io_err) {
+            EdgeKvError::Io(e) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+            e => panic!("unexpected {:?}", e),
+        }
+
+        let wrapped: io::Error = EdgeKvError::KeyTooLarge { size: 3, limit: 2 }.into();
+        assert_eq!(wrapped.kind(), io::ErrorKind::Other);
+
+        let located = EdgeKvError::ValueTooLarge { size: 9, limit: 4 }.at_offset(8);
+        assert_eq!(located.to_string(), "corruption at offset 8: value size 9 exceeds limit of 4 bytes");
+        let kept = EdgeKvError::corruption_at(3, "bad").at_offset(8);
+        assert!(matches!(kept, EdgeKvError::Corruption { offset: Some(3), .. }));
+        assert_eq!(kept.in_file("000002.hint").to_string(), "corruption in 000002.hint at offset 3: bad");
+    }
+}
@@ -0,0 +1,172 @@
+//! Order-preserving key encodings.
+//!
+//! Each component encodes so that comparing the encoded bytes gives the
+//! same order as comparing the native values, and a tuple is just its
+//! components concatenated. Range scans over composite keys such as
+//! `(device_id, timestamp)` therefore come out in the expected order.
+//!
+//! * `u64` is written big-endian.
+//! * `i64` is written big-endian with the sign bit flipped, so negative
+//!   numbers sort before positive ones.
+//! * Byte strings and strings escape `0x00` as `0x00 0xff` and end with
+//!   `0x00 0x01`, so a string sorts before any longer string it prefixes
+//!   and the next component can follow it.
+
+use crate::error::{EdgeKvError, Result};
+
+const ESCAPE: u8 = 0x00;
+const ESCAPED_ZERO: u8 = 0xff;
+const TERMINATOR: u8 = 0x01;
+
+/// Builds an order-preserving key out of one or more components.
+///
+/// ```
+/// use edgekv::keys::KeyEncoder;
+///
+/// let key = KeyEncoder::new().push_str("sensor").push_i64(-5).finish();
+/// assert!(key < KeyEncoder::new().push_str("sensor").push_i64(3).finish());
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct KeyEncoder {
+    buf: Vec<u8>,
+}
+
+impl KeyEncoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push_u64(mut self, value: u64) -> Self {
+        self.buf.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    pub fn push_i64(mut self, value: i64) -> Self {
+        self.buf.extend_from_slice(&((value as u64) ^ (1 << 63)).to_be_bytes());
+        self
+    }
+
+    pub fn push_bytes(mut self, value: &[u8]) -> Self {
+        for b in value {
+            self.buf.push(*b);
+            if *b == ESCAPE {
+                self.buf.push(ESCAPED_ZERO);
+            }
+        }
+        self.buf.push(ESCAPE);
+        self.buf.push(TERMINATOR);
+        self
+    }
+
+    pub fn push_str(self, value: &str) -> Self {
+        self.push_bytes(value.as_bytes())
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// Reads components back out of a key built by [`KeyEncoder`], in the order
+/// they were pushed.
+#[derive(Debug, Clone)]
+pub struct KeyDecoder<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> KeyDecoder<'a> {
+    pub fn new(key: &'a [u8]) -> Self {
+        Self { buf: key }
+    }
+
+    /// True once every component has been read.
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.take_8()?))
+    }
+
+    pub fn read_i64(&mut self) -> Result<i64> {
+        Ok((u64::from_be_bytes(self.take_8()?) ^ (1 << 63)) as i64)
+    }
+
+    pub fn read_bytes(&mut self) -> Result<Vec<u8>> {
+        let mut out = vec![];
+        let mut i = 0;
+        while i < self.buf.len() {
+            if self.buf[i] != ESCAPE {
+                out.push(self.buf[i]);
+                i += 1;
+                continue;
+            }
+            match self.buf.get(i + 1) {
+                Some(&ESCAPED_ZERO) => {
+                    out.push(ESCAPE);
+                    i += 2;
+                }
+                Some(&TERMINATOR) => {
+                    self.buf = &self.buf[i + 2..];
+                    return Ok(out);
+                }
+                _ => return Err(EdgeKvError::InvalidKey("invalid escape in string component".into())),
+            }
+        }
+        Err(EdgeKvError::InvalidKey("unterminated string component".into()))
+    }
+
+    pub fn read_string(&mut self) -> Result<String> {
+        Ok(String::from_utf8(self.read_bytes()?)?)
+    }
+
+    fn take_8(&mut self) -> Result<[u8; 8]> {
+        if self.buf.len() < 8 {
+            return Err(EdgeKvError::InvalidKey("key too short for an 8 byte component".into()));
+        }
+        let mut raw = [0_u8; 8];
+        raw.copy_from_slice(&self.buf[..8]);
+        self.buf = &self.buf[8..];
+        Ok(raw)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::keys::{KeyEncoder, KeyDecoder};
+
+    #[test]
+    fn order_preserving_test() {
+        let ints = [i64::MIN, -300, -1, 0, 1, 255, 256, i64::MAX];
+        for pair in ints.windows(2) {
+            let a = KeyEncoder::new().push_i64(pair[0]).finish();
+            let b = KeyEncoder::new().push_i64(pair[1]).finish();
+            assert!(a < b, "{} !< {}", pair[0], pair[1]);
+        }
+
+        let strs: [&[u8]; 6] = [b"", b"\x00", b"\x00\x00", b"\x00a", b"a", b"ab"];
+        for pair in strs.windows(2) {
+            let a = KeyEncoder::new().push_bytes(pair[0]).push_u64(u64::MAX).finish();
+            let b = KeyEncoder::new().push_bytes(pair[1]).push_u64(0).finish();
+            assert!(a < b, "{:?} !< {:?}", pair[0], pair[1]);
+        }
+    }
+
+    #[test]
+    fn tuple_round_trip_test() {
+        let key = KeyEncoder::new()
+            .push_str("dev\x00ice")
+            .push_i64(-42)
+            .push_u64(7)
+            .push_bytes(b"")
+            .finish();
+        let mut rdr = KeyDecoder::new(&key);
+        assert_eq!(rdr.read_string().unwrap(), "dev\x00ice");
+        assert_eq!(rdr.read_i64().unwrap(), -42);
+        assert_eq!(rdr.read_u64().unwrap(), 7);
+        assert_eq!(rdr.read_bytes().unwrap(), b"");
+        assert!(rdr.is_empty());
+        assert!(rdr.read_u64().is_err());
+        assert!(KeyDecoder::new(b"abc").read_bytes().is_err());
+    }
+}
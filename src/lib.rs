@@ -1,4 +1,18 @@
+mod error;
 mod schema;
+pub mod keys;
+pub mod storage;
+mod manifest;
+mod blob;
+mod merge;
+mod recovery;
+mod verify;
+mod migrate;
+
+pub use error::{EdgeKvError, Result};
+pub use migrate::migrate;
+pub use recovery::{doctor, RecoveryReport};
+pub use verify::{verify, CorruptRecord, OrphanedHint, VerifyReport};
 
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,605 @@
+//! The MANIFEST: an append-only, checksummed log of changes to the set of
+//! live files. Replaying it gives the files in creation order, so opening a
+//! store never depends on directory listing order, and a merge that was
+//! begun but never committed is rolled back the same way on every restart.
+
+use crate::error::{EdgeKvError, Result};
+use crate::merge::{temp_name, DATA_SUFFIX};
+use crate::recovery::RecoveryReport;
+use crate::schema::{crc_checksum, decode_varint, encode_varint, FileHeader, FileKind, FormatVersion};
+use crate::storage::{Storage, StorageFile, StorageReader};
+use std::io::{BufReader, Cursor, Read};
+
+pub(crate) const MANIFEST_FILE: &str = "MANIFEST";
+
+/// Upper bound on a single edit, so a corrupted length can't trigger a
+/// huge allocation.
+const MAX_EDIT_SIZE: u32 = 1024 * 1024;
+
+const TAG_CREATE: u8 = 1;
+const TAG_SEAL: u8 = 2;
+const TAG_MERGE_BEGIN: u8 = 3;
+const TAG_MERGE_COMMIT: u8 = 4;
+const TAG_MERGE_ABORT: u8 = 5;
+const TAG_DELETE: u8 = 6;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum ManifestEdit {
+    /// A new file joined the store as the newest live file.
+    Create(String),
+    /// The file will receive no more appends.
+    Seal(String),
+    /// A merge started that will replace `inputs` with `outputs`. The
+    /// outputs are not live until the matching `MergeCommit`.
+    MergeBegin {
+        id: u64,
+        inputs: Vec<String>,
+        outputs: Vec<String>,
+    },
+    MergeCommit { id: u64 },
+    /// The merge was rolled back and its outputs are garbage.
+    MergeAbort { id: u64 },
+    /// The file left the live set.
+    Delete(String),
+}
+
+fn encode_string(value: &str, buf: &mut Vec<u8>) {
+    encode_varint(value.len() as u64, buf);
+    buf.extend_from_slice(value.as_bytes());
+}
+
+fn decode_string<R: Read>(rdr: &mut R) -> Result<String> {
+    let len = decode_varint(rdr)?;
+    if len > MAX_EDIT_SIZE as u64 {
+        return Err(EdgeKvError::corruption("manifest file name too long"));
+    }
+    let mut raw = vec![0_u8; len as usize];
+    rdr.read_exact(&mut raw)?;
+    String::from_utf8(raw).map_err(|_| EdgeKvError::corruption("manifest file name is not utf-8"))
+}
+
+fn encode_names(names: &[String], buf: &mut Vec<u8>) {
+    encode_varint(names.len() as u64, buf);
+    for name in names {
+        encode_string(name, buf);
+    }
+}
+
+fn decode_names<R: Read>(rdr: &mut R) -> Result<Vec<String>> {
+    let count = decode_varint(rdr)?;
+    (0..count).map(|_| decode_string(rdr)).collect()
+}
+
+impl ManifestEdit {
+    /// Framed as crc (u32, over the payload), payload length (u32), payload.
+    fn encode(&self) -> Vec<u8> {
+        let mut payload = vec![];
+        match self {
+            ManifestEdit::Create(name) => {
+                payload.push(TAG_CREATE);
+                encode_string(name, &mut payload);
+            }
+            ManifestEdit::Seal(name) => {
+                payload.push(TAG_SEAL);
+                encode_string(name, &mut payload);
+            }
+            ManifestEdit::MergeBegin { id, inputs, outputs } => {
+                payload.push(TAG_MERGE_BEGIN);
+                encode_varint(*id, &mut payload);
+                encode_names(inputs, &mut payload);
+                encode_names(outputs, &mut payload);
+            }
+            ManifestEdit::MergeCommit { id } => {
+                payload.push(TAG_MERGE_COMMIT);
+                encode_varint(*id, &mut payload);
+            }
+            ManifestEdit::MergeAbort { id } => {
+                payload.push(TAG_MERGE_ABORT);
+                encode_varint(*id, &mut payload);
+            }
+            ManifestEdit::Delete(name) => {
+                payload.push(TAG_DELETE);
+                encode_string(name, &mut payload);
+            }
+        }
+        let mut buf = vec![];
+        buf.extend_from_slice(&crc_checksum(&payload).to_be_bytes());
+        buf.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&payload);
+        buf
+    }
+
+    fn decode_payload(payload: &[u8]) -> Result<Self> {
+        let mut rdr = Cursor::new(payload);
+        let mut tag = [0_u8; 1];
+        rdr.read_exact(&mut tag)?;
+        let edit = match tag[0] {
+            TAG_CREATE => ManifestEdit::Create(decode_string(&mut rdr)?),
+            TAG_SEAL => ManifestEdit::Seal(decode_string(&mut rdr)?),
+            TAG_MERGE_BEGIN => ManifestEdit::MergeBegin {
+                id: decode_varint(&mut rdr)?,
+                inputs: decode_names(&mut rdr)?,
+                outputs: decode_names(&mut rdr)?,
+            },
+            TAG_MERGE_COMMIT => ManifestEdit::MergeCommit { id: decode_varint(&mut rdr)? },
+            TAG_MERGE_ABORT => ManifestEdit::MergeAbort { id: decode_varint(&mut rdr)? },
+            TAG_DELETE => ManifestEdit::Delete(decode_string(&mut rdr)?),
+            tag => return Err(EdgeKvError::corruption(format!("unknown manifest edit tag {}", tag))),
+        };
+        if rdr.position() != payload.len() as u64 {
+            return Err(EdgeKvError::corruption("trailing bytes in manifest edit"));
+        }
+        Ok(edit)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct LiveFile {
+    pub(crate) name: String,
+    pub(crate) sealed: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct PendingMerge {
+    id: u64,
+    inputs: Vec<String>,
+    outputs: Vec<String>,
+}
+
+/// The live file set the edits so far add up to.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct ManifestState {
+    files: Vec<LiveFile>,
+    pending: Vec<PendingMerge>,
+    /// Files that left the live set, by a committed merge or a `Delete`,
+    /// and haven't been created again since.
+    obsolete: Vec<String>,
+    next_merge_id: u64,
+}
+
+impl ManifestState {
+    fn position(&self, name: &str) -> Result<usize> {
+        self.files
+            .iter()
+            .position(|f| f.name == name)
+            .ok_or_else(|| EdgeKvError::corruption(format!("manifest refers to unknown file {}", name)))
+    }
+
+    fn take_pending(&mut self, id: u64) -> Result<PendingMerge> {
+        match self.pending.iter().position(|m| m.id == id) {
+            Some(i) => Ok(self.pending.remove(i)),
+            None => Err(EdgeKvError::corruption(format!("manifest refers to unknown merge {}", id))),
+        }
+    }
+
+    fn apply(&mut self, edit: &ManifestEdit) -> Result<()> {
+        match edit {
+            ManifestEdit::Create(name) => {
+                if self.files.iter().any(|f| &f.name == name) {
+                    return Err(EdgeKvError::corruption(format!("manifest creates {} twice", name)));
+                }
+                self.files.push(LiveFile {
+                    name: name.clone(),
+                    sealed: false,
+                });
+                self.obsolete.retain(|f| f != name);
+            }
+            ManifestEdit::Seal(name) => {
+                let i = self.position(name)?;
+                self.files[i].sealed = true;
+            }
+            ManifestEdit::MergeBegin { id, inputs, outputs } => {
+                for input in inputs {
+                    self.position(input)?;
+                }
+                if let Some(output) = outputs.iter().find(|o| self.files.iter().any(|f| &f.name == *o)) {
+                    return Err(EdgeKvError::corruption(format!("merge output {} is already live", output)));
+                }
+                self.pending.push(PendingMerge {
+                    id: *id,
+                    inputs: inputs.clone(),
+                    outputs: outputs.clone(),
+                });
+                self.next_merge_id = self.next_merge_id.max(id + 1);
+            }
+            ManifestEdit::MergeCommit { id } => {
+                let merge = self.take_pending(*id)?;
+                // Outputs take the place of the oldest input, keeping them
+                // older than any file created while the merge ran.
+                let at = merge
+                    .inputs
+                    .iter()
+                    .map(|input| self.position(input))
+                    .collect::<Result<Vec<_>>>()?
+                    .into_iter()
+                    .min()
+                    .unwrap_or(self.files.len());
+                let outputs = merge.outputs.iter().map(|name| LiveFile {
+                    name: name.clone(),
+                    sealed: true,
+                });
+                self.files.splice(at..at, outputs);
+                self.files.retain(|f| !merge.inputs.contains(&f.name));
+                self.obsolete.retain(|f| !merge.outputs.contains(f));
+                self.obsolete.extend(merge.inputs);
+            }
+            ManifestEdit::MergeAbort { id } => {
+                self.take_pending(*id)?;
+            }
+            ManifestEdit::Delete(name) => {
+                let i = self.position(name)?;
+                self.files.remove(i);
+                self.obsolete.push(name.clone());
+            }
+        }
+        Ok(())
+    }
+}
+
+pub(crate) struct Manifest<F: StorageFile> {
+    file: F,
+    state: ManifestState,
+    abandoned: Vec<String>,
+}
+
+impl<F: StorageFile> Manifest<F> {
+    /// Opens the manifest and replays it.
+    ///
+    /// A store without one, or with an empty one, gets a manifest listing
+    /// the data files already present, oldest file id first. It is written
+    /// under a temporary name and renamed into place, so a crash can't
+    /// leave a manifest that lists only some of them.
+    ///
+    /// An edit that runs past the end of the file, with no intact edit
+    /// after it, is a torn write. It is cut off and counted in `report`.
+    /// Any other damaged edit is corruption, wherever it occurs. Merges left uncommitted are aborted
+    /// in the log, and their outputs become
+    /// [`abandoned_files`](Self::abandoned_files) for the caller to delete.
+    #[allow(dead_code)] // the store's open will call this
+    pub(crate) fn open<S: Storage<File = F>>(storage: &S, report: &mut RecoveryReport) -> Result<Self> {
+        if storage.open(MANIFEST_FILE)?.is_empty()? {
+            let mut buf = FileHeader::new(FileKind::Manifest).encode();
+            for name in existing_data_files(storage)? {
+                buf.extend(ManifestEdit::Create(name).encode());
+            }
+            let tmp_name = temp_name(MANIFEST_FILE);
+            if storage.list()?.contains(&tmp_name) {
+                storage.remove(&tmp_name)?;
+            }
+            let mut tmp = storage.open(&tmp_name)?;
+            tmp.append(&buf)?;
+            tmp.sync()?;
+            storage.rename(&tmp_name, MANIFEST_FILE)?;
+        }
+
+        let mut file = storage.open(MANIFEST_FILE)?;
+        let len = file.len()?;
+        let mut state = ManifestState::default();
+        let torn_at = replay(&file, len, &mut state).map_err(|e| e.in_file(MANIFEST_FILE))?;
+        if let Some(position) = torn_at {
+            file.truncate(position)?;
+            file.sync()?;
+            report.bytes_truncated += len - position;
+            report.files_repaired.push(MANIFEST_FILE.to_owned());
+        }
+
+        let mut manifest = Self {
+            file,
+            state,
+            abandoned: vec![],
+        };
+        for merge in manifest.state.pending.clone() {
+            manifest.record(ManifestEdit::MergeAbort { id: merge.id })?;
+            manifest.abandoned.extend(merge.outputs);
+        }
+        Ok(manifest)
+    }
+
+    /// Applies `edit` and appends it durably; the edit only takes effect in
+    /// memory if it is valid against the current state. If the append or
+    /// sync fails, the file is truncated back so the next edit doesn't land
+    /// after a partial one.
+    pub(crate) fn record(&mut self, edit: ManifestEdit) -> Result<()> {
+        let mut next = self.state.clone();
+        next.apply(&edit)?;
+        let len = self.file.len()?;
+        if let Err(e) = self.file.append(&edit.encode()).and_then(|_| self.file.sync()) {
+            self.file.truncate(len)?;
+            return Err(e);
+        }
+        self.state = next;
+        Ok(())
+    }
+
+    /// Records the start of a merge and returns its id for the commit.
+    pub(crate) fn begin_merge(&mut self, inputs: Vec<String>, outputs: Vec<String>) -> Result<u64> {
+        let id = self.state.next_merge_id;
+        self.record(ManifestEdit::MergeBegin { id, inputs, outputs })?;
+        Ok(id)
+    }
+
+    pub(crate) fn commit_merge(&mut self, id: u64) -> Result<()> {
+        self.record(ManifestEdit::MergeCommit { id })
+    }
+
+    /// Live files, oldest first.
+    pub(crate) fn live_files(&self) -> &[LiveFile] {
+        &self.state.files
+    }
+
+    /// Outputs of merges that were rolled back when the manifest was opened.
+    pub(crate) fn abandoned_files(&self) -> &[String] {
+        &self.abandoned
+    }
+
+    /// Files that were live once and have since been merged away or
+    /// deleted. Their contents may still be on disk.
+    pub(crate) fn obsolete_files(&self) -> &[String] {
+        &self.state.obsolete
+    }
+}
+
+/// Data files in `storage`, ordered by their numeric file id.
+fn existing_data_files<S: Storage>(storage: &S) -> Result<Vec<String>> {
+    let mut names: Vec<String> = storage.list()?.into_iter().filter(|name| name.ends_with(DATA_SUFFIX)).collect();
+    names.sort_by_key(|name| (name.trim_end_matches(DATA_SUFFIX).parse::<u64>().ok(), name.clone()));
+    Ok(names)
+}
+
+/// Applies every edit in `file` to `state`. Returns where a torn final
+/// edit starts, if there is one.
+fn replay<F: StorageFile>(file: &F, len: u64, state: &mut ManifestState) -> Result<Option<u64>> {
+    let mut rdr = BufReader::new(StorageReader::new(file, 0));
+    let header = FileHeader::read(&mut rdr, FileKind::Manifest)?;
+    if header.version() == FormatVersion::V1 {
+        return Err(EdgeKvError::corruption("manifest has no header"));
+    }
+    let mut position = FileHeader::SIZE;
+    while position < len {
+        let mut frame = [0_u8; 8];
+        if len - position < frame.len() as u64 {
+            return Ok(Some(position));
+        }
+        rdr.read_exact(&mut frame)?;
+        let crc = u32::from_be_bytes([frame[0], frame[1], frame[2], frame[3]]);
+        let size = u32::from_be_bytes([frame[4], frame[5], frame[6], frame[7]]);
+        if size > MAX_EDIT_SIZE {
+            return Err(EdgeKvError::corruption_at(position, format!("manifest edit size {} exceeds limit", size)));
+        }
+        let end = position + frame.len() as u64 + size as u64;
+        if end > len {
+            if edit_follows(file, position + 1, len)? {
+                return Err(EdgeKvError::corruption_at(position, "manifest edit overruns an intact edit"));
+            }
+            return Ok(Some(position));
+        }
+        let mut payload = vec![0_u8; size as usize];
+        rdr.read_exact(&mut payload)?;
+        if crc != crc_checksum(&payload) {
+            return Err(EdgeKvError::corruption_at(position, "manifest edit checksum mismatch"));
+        }
+        ManifestEdit::decode_payload(&payload)
+            .and_then(|edit| state.apply(&edit))
+            .map_err(|e| e.at_offset(position))?;
+        position = end;
+    }
+    Ok(None)
+}
+
+/// True if an intact edit starts anywhere from `from` on, in which case
+/// an edit before it that runs past the end has a damaged length and was
+/// not torn.
+fn edit_follows<F: StorageFile>(file: &F, from: u64, len: u64) -> Result<bool> {
+    let mut rest = vec![0_u8; len.saturating_sub(from) as usize];
+    file.read_at(from, &mut rest)?;
+    for start in 0..rest.len() {
+        let frame = &rest[start..];
+        if frame.len() < 8 {
+            break;
+        }
+        let crc = u32::from_be_bytes([frame[0], frame[1], frame[2], frame[3]]);
+        let size = u32::from_be_bytes([frame[4], frame[5], frame[6], frame[7]]);
+        if let Some(payload) = frame[8..].get(..size as usize) {
+            if crc == crc_checksum(payload) && ManifestEdit::decode_payload(payload).is_ok() {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::EdgeKvError;
+    use crate::manifest::{Manifest, ManifestEdit, MANIFEST_FILE};
+    use crate::recovery::RecoveryReport;
+    use crate::storage::{MemFile, MemStorage, Storage, StorageFile};
+    use std::io;
+
+    fn open(storage: &MemStorage) -> crate::error::Result<Manifest<MemFile>> {
+        Manifest::open(storage, &mut RecoveryReport::default())
+    }
+
+    fn overwrite(storage: &MemStorage, edit: impl FnOnce(&mut Vec<u8>)) {
+        let file = storage.open(MANIFEST_FILE).unwrap();
+        let mut bytes = vec![0_u8; file.len().unwrap() as usize];
+        file.read_at(0, &mut bytes).unwrap();
+        edit(&mut bytes);
+        storage.remove(MANIFEST_FILE).unwrap();
+        storage.open(MANIFEST_FILE).unwrap().append(&bytes).unwrap();
+    }
+
+    fn names<F: StorageFile>(manifest: &Manifest<F>) -> Vec<(&str, bool)> {
+        manifest.live_files().iter().map(|f| (f.name.as_str(), f.sealed)).collect()
+    }
+
+    #[test]
+    fn replay_and_merge_test() {
+        let storage = MemStorage::new();
+        let mut manifest = open(&storage).unwrap();
+        for name in ["000001.data", "000002.data", "000003.data"].iter() {
+            manifest.record(ManifestEdit::Create(name.to_string())).unwrap();
+        }
+        manifest.record(ManifestEdit::Seal("000001.data".into())).unwrap();
+        manifest.record(ManifestEdit::Seal("000002.data".into())).unwrap();
+        assert!(manifest.record(ManifestEdit::Seal("missing".into())).is_err());
+
+        let id = manifest
+            .begin_merge(vec!["000001.data".into(), "000002.data".into()], vec!["000004.data".into()])
+            .unwrap();
+        manifest.commit_merge(id).unwrap();
+        manifest.record(ManifestEdit::Delete("000003.data".into())).unwrap();
+
+        let reopened = open(&storage).unwrap();
+        assert_eq!(names(&reopened), vec![("000004.data", true)]);
+        assert!(reopened.abandoned_files().is_empty());
+        assert_eq!(reopened.obsolete_files(), ["000001.data", "000002.data", "000003.data"]);
+    }
+
+    #[test]
+    fn uncommitted_merge_and_torn_tail_test() {
+        let storage = MemStorage::new();
+        let mut manifest = open(&storage).unwrap();
+        manifest.record(ManifestEdit::Create("000001.data".into())).unwrap();
+        manifest.record(ManifestEdit::Create("000002.data".into())).unwrap();
+        manifest
+            .begin_merge(vec!["000001.data".into()], vec!["000003.data".into()])
+            .unwrap();
+        storage.open(MANIFEST_FILE).unwrap().append(&[0, 0, 0, 0, 0, 0, 0, 9, 1]).unwrap();
+
+        let mut report = RecoveryReport::default();
+        let mut reopened = Manifest::open(&storage, &mut report).unwrap();
+        assert_eq!(names(&reopened), vec![("000001.data", false), ("000002.data", false)]);
+        assert_eq!(reopened.abandoned_files(), ["000003.data".to_string()]);
+        assert_eq!(report.bytes_truncated, 9);
+        assert_eq!(report.files_repaired, vec![MANIFEST_FILE]);
+
+        let id = reopened.begin_merge(vec!["000001.data".into()], vec!["000004.data".into()]).unwrap();
+        assert_eq!(id, 1);
+        assert!(reopened.begin_merge(vec![], vec!["000002.data".into()]).is_err());
+        reopened.commit_merge(id).unwrap();
+        let again = open(&storage).unwrap();
+        assert_eq!(names(&again), vec![("000004.data", true), ("000002.data", false)]);
+        assert!(again.abandoned_files().is_empty());
+    }
+
+    /// Writes half of each append, then fails, while `failing` is set.
+    struct FlakyFile {
+        inner: MemFile,
+        failing: bool,
+    }
+
+    impl StorageFile for FlakyFile {
+        fn append(&mut self, buf: &[u8]) -> crate::error::Result<u64> {
+            if self.failing {
+                self.inner.append(&buf[..buf.len() / 2])?;
+                return Err(io::Error::other("disk full").into());
+            }
+            self.inner.append(buf)
+        }
+
+        fn read_at(&self, offset: u64, buf: &mut [u8]) -> crate::error::Result<()> {
+            self.inner.read_at(offset, buf)
+        }
+
+        fn len(&self) -> crate::error::Result<u64> {
+            self.inner.len()
+        }
+
+        fn truncate(&mut self, len: u64) -> crate::error::Result<()> {
+            self.inner.truncate(len)
+        }
+
+        fn sync(&self) -> crate::error::Result<()> {
+            self.inner.sync()
+        }
+    }
+
+    #[test]
+    fn failed_record_test() {
+        let storage = MemStorage::new();
+        let opened = open(&storage).unwrap();
+        let mut manifest = Manifest {
+            file: FlakyFile {
+                inner: storage.open(MANIFEST_FILE).unwrap(),
+                failing: true,
+            },
+            state: opened.state.clone(),
+            abandoned: vec![],
+        };
+        let len = manifest.file.len().unwrap();
+        assert!(manifest.record(ManifestEdit::Create("000001.data".into())).is_err());
+        assert_eq!(manifest.file.len().unwrap(), len);
+        assert!(manifest.live_files().is_empty());
+
+        manifest.file.failing = false;
+        manifest.record(ManifestEdit::Create("000002.data".into())).unwrap();
+        assert_eq!(names(&open(&storage).unwrap()), vec![("000002.data", false)]);
+    }
+
+    #[test]
+    fn mid_file_corruption_test() {
+        let storage = MemStorage::new();
+        let mut manifest = open(&storage).unwrap();
+        manifest.record(ManifestEdit::Create("000001.data".into())).unwrap();
+        manifest.record(ManifestEdit::Create("000002.data".into())).unwrap();
+        manifest.record(ManifestEdit::Create("000003.data".into())).unwrap();
+        let len = storage.open(MANIFEST_FILE).unwrap().len().unwrap();
+
+        overwrite(&storage, |bytes| bytes[20] ^= 0xff);
+        assert!(open(&storage).is_err());
+
+        // A flipped bit in the first edit's length must not be taken for a
+        // torn tail and cut off the whole log.
+        overwrite(&storage, |bytes| {
+            bytes[20] ^= 0xff;
+            bytes[12] ^= 0x80;
+        });
+        match open(&storage) {
+            Err(EdgeKvError::Corruption { file, offset, .. }) => {
+                assert_eq!(file.as_deref(), Some(MANIFEST_FILE));
+                assert_eq!(offset, Some(8));
+            }
+            _ => panic!("expected corruption"),
+        }
+        assert_eq!(storage.open(MANIFEST_FILE).unwrap().len().unwrap(), len);
+
+        // Nor a flip that keeps the length in bounds but runs it past the
+        // end: the edits after it are still intact.
+        overwrite(&storage, |bytes| {
+            bytes[12] ^= 0x80;
+            bytes[13] ^= 0x01;
+        });
+        match open(&storage) {
+            Err(EdgeKvError::Corruption { offset, .. }) => assert_eq!(offset, Some(8)),
+            _ => panic!("expected corruption"),
+        }
+        assert_eq!(storage.open(MANIFEST_FILE).unwrap().len().unwrap(), len);
+
+        // A complete final edit with a bad crc is corruption too.
+        overwrite(&storage, |bytes| {
+            bytes[13] ^= 0x01;
+            let last = bytes.len() - 1;
+            bytes[last] ^= 0xff;
+        });
+        assert!(open(&storage).is_err());
+        assert_eq!(storage.open(MANIFEST_FILE).unwrap().len().unwrap(), len);
+    }
+
+    #[test]
+    fn bootstrap_from_existing_files_test() {
+        let storage = MemStorage::new();
+        for name in ["000010.data", "000002.data", "000002.hint", "000003.data.tmp"].iter() {
+            storage.open(name).unwrap().append(b"x").unwrap();
+        }
+        storage.open(MANIFEST_FILE).unwrap();
+
+        let manifest = open(&storage).unwrap();
+        assert_eq!(names(&manifest), vec![("000002.data", false), ("000010.data", false)]);
+        assert!(!storage.list().unwrap().iter().any(|name| name.starts_with(MANIFEST_FILE) && name != MANIFEST_FILE));
+
+        storage.open("000011.data").unwrap();
+        let reopened = open(&storage).unwrap();
+        assert_eq!(names(&reopened), names(&manifest));
+    }
+}
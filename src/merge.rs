@@ -0,0 +1,203 @@
+//! Committing the output of a merge so that a crash at any point leaves
+//! either the inputs or the outputs live, never neither.
+//!
+//! The protocol, for data files tracked by the [`Manifest`]:
+//!
+//! 1. The merge writes every output, and its hint file, under
+//!    [`temp_name`]. [`commit_merge`] syncs them.
+//! 2. A `MergeBegin` edit naming the inputs and outputs is made durable.
+//! 3. Each temp file is renamed to its final name. Renames are durable
+//!    when they return.
+//! 4. A `MergeCommit` edit is made durable. From here on the outputs are
+//!    live and the inputs are not.
+//! 5. The input data and hint files are removed.
+//!
+//! A crash before step 4 completes leaves the merge uncommitted, so
+//! [`Manifest::open`] aborts it and the inputs stay live, untouched. A
+//! crash after it leaves the outputs live, complete and synced. Either
+//! way, [`remove_obsolete_files`] on open deletes whatever is left over:
+//! temp files, outputs of aborted merges and inputs of committed ones.
+
+use crate::error::{EdgeKvError, Result};
+use crate::manifest::Manifest;
+use crate::storage::{Storage, StorageFile};
+
+pub(crate) const DATA_SUFFIX: &str = ".data";
+const HINT_SUFFIX: &str = ".hint";
+const TEMP_SUFFIX: &str = ".tmp";
+
+/// Name a file is written under until it is committed.
+pub(crate) fn temp_name(name: &str) -> String {
+    format!("{}{}", name, TEMP_SUFFIX)
+}
+
+/// Hint file belonging to the data file `data_name`.
+pub(crate) fn hint_file_name(data_name: &str) -> String {
+    format!("{}{}", data_name.strip_suffix(DATA_SUFFIX).unwrap_or(data_name), HINT_SUFFIX)
+}
+
+/// Replaces the data files `inputs` with `outputs`, following the protocol
+/// above. Each output, and optionally its hint file, must already have
+/// been written under its [`temp_name`].
+#[allow(dead_code)] // the store's merge will call this
+pub(crate) fn commit_merge<S: Storage>(
+    storage: &S,
+    manifest: &mut Manifest<S::File>,
+    inputs: Vec<String>,
+    outputs: Vec<String>,
+) -> Result<()> {
+    let existing = storage.list()?;
+    let mut renames = vec![];
+    for output in &outputs {
+        if manifest.live_files().iter().any(|f| &f.name == output) {
+            return Err(EdgeKvError::corruption(format!("merge output {} is already live", output)));
+        }
+        let tmp = temp_name(output);
+        if !existing.contains(&tmp) {
+            return Err(EdgeKvError::corruption(format!("merge output {} was not written", tmp)));
+        }
+        renames.push((tmp, output.clone()));
+        let hint = hint_file_name(output);
+        if existing.contains(&temp_name(&hint)) {
+            renames.push((temp_name(&hint), hint));
+        }
+    }
+    for (tmp, _) in &renames {
+        storage.open(tmp)?.sync()?;
+    }
+
+    let id = manifest.begin_merge(inputs.clone(), outputs)?;
+    for (tmp, name) in &renames {
+        storage.rename(tmp, name)?;
+    }
+    manifest.commit_merge(id)?;
+
+    let existing = storage.list()?;
+    for input in &inputs {
+        for name in [input.clone(), hint_file_name(input)].iter() {
+            if existing.contains(name) {
+                storage.remove(name)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Removes temp files, the outputs of merges `manifest` aborted and the
+/// files it records as merged away or deleted, each with its hint file.
+/// Files the manifest doesn't know about are left alone, and so is any
+/// file that is live. Call after [`Manifest::open`] and before reading any
+/// data file. Returns the names removed.
+#[allow(dead_code)] // the store's open will call this
+pub(crate) fn remove_obsolete_files<S: Storage>(storage: &S, manifest: &Manifest<S::File>) -> Result<Vec<String>> {
+    let live: Vec<&str> = manifest.live_files().iter().map(|f| f.name.as_str()).collect();
+    let mut obsolete = vec![];
+    for data_name in manifest.abandoned_files().iter().chain(manifest.obsolete_files()) {
+        if !live.contains(&data_name.as_str()) {
+            obsolete.push(data_name.clone());
+            obsolete.push(hint_file_name(data_name));
+        }
+    }
+    let mut removed = vec![];
+    for name in storage.list()? {
+        if name.ends_with(TEMP_SUFFIX) || obsolete.contains(&name) {
+            storage.remove(&name)?;
+            removed.push(name);
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::manifest::{Manifest, ManifestEdit, MANIFEST_FILE};
+    use crate::merge::{commit_merge, hint_file_name, remove_obsolete_files, temp_name};
+    use crate::recovery::RecoveryReport;
+    use crate::storage::{MemFile, MemStorage, Storage, StorageFile};
+
+    fn setup() -> (MemStorage, Manifest<MemFile>) {
+        let storage = MemStorage::new();
+        let mut manifest = Manifest::open(&storage, &mut RecoveryReport::default()).unwrap();
+        for name in ["000001.data", "000002.data", "000003.data"].iter() {
+            storage.open(name).unwrap().append(name.as_bytes()).unwrap();
+            storage.open(&hint_file_name(name)).unwrap().append(b"hint").unwrap();
+            manifest.record(ManifestEdit::Create(name.to_string())).unwrap();
+        }
+        storage.open(&temp_name("000004.data")).unwrap().append(b"merged").unwrap();
+        storage.open(&temp_name("000004.hint")).unwrap().append(b"hint").unwrap();
+        (storage, manifest)
+    }
+
+    fn live(storage: &MemStorage) -> Vec<String> {
+        let manifest = Manifest::open(storage, &mut RecoveryReport::default()).unwrap();
+        remove_obsolete_files(storage, &manifest).unwrap();
+        manifest.live_files().iter().map(|f| f.name.clone()).collect()
+    }
+
+    #[test]
+    fn commit_merge_test() {
+        let (storage, mut manifest) = setup();
+        let inputs = vec!["000001.data".to_string(), "000002.data".to_string()];
+        commit_merge(&storage, &mut manifest, inputs, vec!["000004.data".into()]).unwrap();
+        assert_eq!(live(&storage), vec!["000004.data", "000003.data"]);
+        assert_eq!(
+            storage.list().unwrap(),
+            vec!["000003.data", "000003.hint", "000004.data", "000004.hint", "MANIFEST"]
+        );
+        assert_eq!(storage.open("000004.data").unwrap().len().unwrap(), 6);
+
+        assert!(commit_merge(&storage, &mut manifest, vec!["000003.data".into()], vec!["000005.data".into()]).is_err());
+    }
+
+    #[test]
+    fn crash_during_merge_test() {
+        // Crash before MergeBegin: only the temp files exist.
+        let (storage, _) = setup();
+        assert_eq!(live(&storage), vec!["000001.data", "000002.data", "000003.data"]);
+        assert_eq!(storage.list().unwrap().len(), 7);
+
+        // Crash after MergeBegin and one rename.
+        let (storage, mut manifest) = setup();
+        manifest
+            .begin_merge(vec!["000001.data".into()], vec!["000004.data".into()])
+            .unwrap();
+        storage.rename(&temp_name("000004.data"), "000004.data").unwrap();
+        drop(manifest);
+        assert_eq!(live(&storage), vec!["000001.data", "000002.data", "000003.data"]);
+        assert!(!storage.list().unwrap().iter().any(|name| name.starts_with("000004")));
+        let mut input = vec![0_u8; 11];
+        storage.open("000001.data").unwrap().read_at(0, &mut input).unwrap();
+        assert_eq!(&input, b"000001.data");
+
+        // Crash after MergeCommit, before the inputs were removed.
+        let (storage, mut manifest) = setup();
+        let id = manifest
+            .begin_merge(vec!["000001.data".into()], vec!["000004.data".into()])
+            .unwrap();
+        storage.rename(&temp_name("000004.data"), "000004.data").unwrap();
+        storage.rename(&temp_name("000004.hint"), "000004.hint").unwrap();
+        manifest.commit_merge(id).unwrap();
+        drop(manifest);
+        assert_eq!(live(&storage), vec!["000004.data", "000002.data", "000003.data"]);
+        assert!(!storage.list().unwrap().iter().any(|name| name.starts_with("000001")));
+    }
+
+    #[test]
+    fn remove_obsolete_files_keeps_unknown_files_test() {
+        // A store from before the manifest existed: every data file is
+        // adopted as live and nothing is removed.
+        let storage = MemStorage::new();
+        for name in ["000001.data", "000001.hint", "000002.data"].iter() {
+            storage.open(name).unwrap().append(b"x").unwrap();
+        }
+        assert_eq!(live(&storage), vec!["000001.data", "000002.data"]);
+        assert_eq!(storage.list().unwrap(), vec!["000001.data", "000001.hint", "000002.data", MANIFEST_FILE]);
+
+        // A file the manifest never recorded is not its to remove.
+        storage.open("000003.data").unwrap().append(b"x").unwrap();
+        let mut manifest = Manifest::open(&storage, &mut RecoveryReport::default()).unwrap();
+        manifest.record(ManifestEdit::Delete("000002.data".into())).unwrap();
+        assert_eq!(remove_obsolete_files(&storage, &manifest).unwrap(), vec!["000002.data"]);
+        assert_eq!(storage.list().unwrap(), vec!["000001.data", "000001.hint", "000003.data", MANIFEST_FILE]);
+    }
+}
@@ -0,0 +1,127 @@
+//! Upgrading a store's data and hint files to the current format version.
+
+use crate::error::Result;
+use crate::merge::{hint_file_name, temp_name, DATA_SUFFIX};
+use crate::schema::{migrate_data, FileHeader, FileKind, FormatVersion};
+use crate::storage::{Storage, StorageFile, StorageReader};
+use std::io::BufReader;
+
+/// Rewrites every data file in `storage` that is not in the current format
+/// version, with a new hint file to match, and returns the names of the
+/// data files migrated. Files already current are left alone, so running
+/// it again is harmless.
+///
+/// Both replacements are written under temporary names and synced first.
+/// The old hint file is removed before the data file is renamed into
+/// place: its offsets are wrong for the new file, and a V1 hint file has
+/// no crc to give that away. A crash part way therefore leaves at worst a
+/// data file without a hint file, which is rebuilt by scanning.
+///
+/// A record that fails its crc stops the migration with an error, since
+/// carrying it over would give it a fresh, valid crc. Run
+/// [`doctor`](crate::doctor) first.
+///
+/// ```
+/// use edgekv::storage::MemStorage;
+///
+/// assert!(edgekv::migrate(&MemStorage::new()).unwrap().is_empty());
+/// ```
+pub fn migrate<S: Storage>(storage: &S) -> Result<Vec<String>> {
+    let names = storage.list()?;
+    let mut migrated = vec![];
+    for data_name in names.iter().filter(|name| name.ends_with(DATA_SUFFIX)) {
+        let data = storage.open(data_name)?;
+        let header = FileHeader::read(&mut StorageReader::new(&data, 0), FileKind::Data)
+            .map_err(|e| e.in_file(data_name))?;
+        if header.version() == FormatVersion::CURRENT {
+            continue;
+        }
+
+        let hint_name = hint_file_name(data_name);
+        let data_tmp = temp_name(data_name);
+        let hint_tmp = temp_name(&hint_name);
+        for tmp in [&data_tmp, &hint_tmp].iter() {
+            if names.contains(tmp) {
+                storage.remove(tmp)?;
+            }
+        }
+        let mut new_data = storage.open(&data_tmp)?;
+        let mut hint_buf = vec![];
+        let mut rdr = BufReader::new(StorageReader::new(&data, 0));
+        if let Err(e) = migrate_data(&mut rdr, &mut new_data, &mut hint_buf) {
+            storage.remove(&data_tmp)?;
+            return Err(e.in_file(data_name));
+        }
+        new_data.sync()?;
+        let mut new_hint = storage.open(&hint_tmp)?;
+        new_hint.append(&hint_buf)?;
+        new_hint.sync()?;
+
+        if names.contains(&hint_name) {
+            storage.remove(&hint_name)?;
+        }
+        storage.rename(&data_tmp, data_name)?;
+        storage.rename(&hint_tmp, &hint_name)?;
+        migrated.push(data_name.clone());
+    }
+    Ok(migrated)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::migrate::migrate;
+    use crate::schema::{read_hint_file, scan_data_file, DataEntry, Encoder, FileHeader, FileKind, FormatVersion, HintEntry};
+    use crate::storage::{MemStorage, Storage, StorageFile, StorageReader};
+    use crate::verify::verify;
+    use std::io::BufReader;
+
+    #[test]
+    fn migrate_test() {
+        let storage = MemStorage::new();
+        let mut legacy = storage.open("000001.data").unwrap();
+        let mut legacy_hint = vec![];
+        for i in 0..3_u8 {
+            let entry = DataEntry::new(0, vec![i], vec![i; 10]);
+            let position = legacy.append(&entry.encode_version(FormatVersion::V1)).unwrap();
+            legacy_hint.extend(HintEntry::from(&entry, position).encode_version(FormatVersion::V1));
+        }
+        storage.open("000001.hint").unwrap().append(&legacy_hint).unwrap();
+        let mut current = storage.open("000002.data").unwrap();
+        current.append(&FileHeader::new(FileKind::Data).encode()).unwrap();
+        current.append(&DataEntry::new(0, b"new".to_vec(), b"v".to_vec()).encode()).unwrap();
+        let untouched = current.len().unwrap();
+
+        assert_eq!(migrate(&storage).unwrap(), vec!["000001.data"]);
+        assert_eq!(storage.list().unwrap(), vec!["000001.data", "000001.hint", "000002.data"]);
+        assert_eq!(storage.open("000002.data").unwrap().len().unwrap(), untouched);
+        let checked = verify(&storage).unwrap();
+        assert!(checked.is_clean());
+        assert_eq!(checked.records_checked, 4);
+
+        let data = storage.open("000001.data").unwrap();
+        let hint = storage.open("000001.hint").unwrap();
+        let scanned = scan_data_file("000001.data", &mut BufReader::new(StorageReader::new(&data, 0))).unwrap();
+        assert_eq!(read_hint_file(&mut BufReader::new(StorageReader::new(&hint, 0))).unwrap(), scanned);
+        let header = FileHeader::read(&mut StorageReader::new(&data, 0), FileKind::Data).unwrap();
+        assert_eq!(header.version(), FormatVersion::CURRENT);
+
+        assert!(migrate(&storage).unwrap().is_empty());
+    }
+
+    #[test]
+    fn migrate_refuses_crc_failure_test() {
+        let storage = MemStorage::new();
+        let mut bad = DataEntry::new(0, b"k".to_vec(), b"v".to_vec()).encode_version(FormatVersion::V1);
+        let last = bad.len() - 1;
+        bad[last] ^= 0xff;
+        storage.open("000001.data").unwrap().append(&bad).unwrap();
+
+        let err = migrate(&storage).unwrap_err();
+        assert!(err.to_string().starts_with("corruption in 000001.data"));
+        assert_eq!(storage.list().unwrap(), vec!["000001.data"]);
+        let data = storage.open("000001.data").unwrap();
+        let mut contents = vec![0_u8; data.len().unwrap() as usize];
+        data.read_at(0, &mut contents).unwrap();
+        assert_eq!(contents, bad);
+    }
+}
@@ -0,0 +1,436 @@
+//! Detecting torn and partially written records after an unclean shutdown.
+
+use crate::error::{EdgeKvError, Result};
+use crate::merge::{hint_file_name, temp_name, DATA_SUFFIX};
+use crate::schema::{
+    read_hint_file, write_hint_file, DataEntry, FileHeader, FileKind, FormatVersion, HintEntry, Records, SizeLimits,
+};
+use crate::storage::{Storage, StorageFile, StorageReader};
+use crate::verify::CorruptRecord;
+use std::io::{self, BufReader};
+
+/// What recovery found and changed, so devices can log exactly what was
+/// lost after an unclean shutdown instead of silently diverging.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RecoveryReport {
+    /// Records that decoded but failed their crc. They are not indexed, and
+    /// [`doctor`] rewrites their files without them. Files [`doctor`]
+    /// leaves alone because of a corrupt record don't count here.
+    pub records_skipped: u64,
+    /// Bytes cut off the end of data files, starting at a torn final
+    /// record.
+    pub bytes_truncated: u64,
+    /// Records that could not be decoded with more data after them. The
+    /// rest of such a file cannot be located, so recovery stops there and
+    /// leaves the file alone.
+    pub corrupt_records: Vec<CorruptRecord>,
+    /// Data files that were truncated or rewritten without their skipped
+    /// records, and hint files that were rebuilt.
+    pub files_repaired: Vec<String>,
+    /// Nothing was written; the report describes what a real run would do.
+    pub dry_run: bool,
+}
+
+impl RecoveryReport {
+    pub fn is_clean(&self) -> bool {
+        self.records_skipped == 0 && self.bytes_truncated == 0 && self.corrupt_records.is_empty()
+    }
+}
+
+/// Scans the data file `name` and returns hint entries for its intact
+/// records. A record that decodes but fails its crc is skipped. A record
+/// that runs past the end of the file is a torn write, and the file is
+/// truncated there and synced; with `dry_run` the truncation is only
+/// reported.
+///
+/// A record only counts as torn if no intact record can be found after
+/// it. Otherwise its size field is damaged, and like any other decode
+/// failure, such as unknown flags or an impossible size, it is added to
+/// `corrupt_records` and ends the scan without truncating, so recovery
+/// never cuts off data it merely could not read. I/O errors other than
+/// running out of data are returned.
+pub(crate) fn recover_data_file<F: StorageFile>(
+    name: &str,
+    file: &mut F,
+    dry_run: bool,
+    report: &mut RecoveryReport,
+) -> Result<Vec<HintEntry>> {
+    let len = file.len()?;
+    let mut hints = vec![];
+    let mut skipped = 0;
+    let torn_at = {
+        let mut records = Records::new(BufReader::new(StorageReader::new(&*file, 0))).map_err(|e| e.in_file(name))?;
+        loop {
+            match records.next() {
+                None => break None,
+                Some(Ok((position, entry))) if entry.check_crc() => hints.push(HintEntry::from(&entry, position)),
+                Some(Ok(_)) => skipped += 1,
+                Some(Err(EdgeKvError::Io(e))) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    let position = records.position();
+                    match find_intact_record(&*file, records.version(), position + 1, len)? {
+                        None => break Some(position),
+                        Some(next) => {
+                            let e = EdgeKvError::corruption(format!("record overruns the intact record at offset {}", next));
+                            report.corrupt_records.push(CorruptRecord::from_error(name, position, e));
+                            break None;
+                        }
+                    }
+                }
+                Some(Err(EdgeKvError::Io(e))) => return Err(e.into()),
+                Some(Err(e)) => {
+                    report.corrupt_records.push(CorruptRecord::from_error(name, records.position(), e));
+                    break None;
+                }
+            }
+        }
+    };
+
+    if let Some(position) = torn_at {
+        if !dry_run {
+            file.truncate(position)?;
+            file.sync()?;
+        }
+        report.bytes_truncated += len - position;
+    }
+    report.records_skipped += skipped;
+    report.dry_run |= dry_run;
+    if torn_at.is_some() {
+        report.files_repaired.push(name.to_owned());
+    }
+    Ok(hints)
+}
+
+/// Offset of the first record at or after `from` that decodes and passes
+/// its crc. Sizes are capped at the bytes left in the file, so each guess
+/// fails as soon as its header is read unless it could fit.
+fn find_intact_record<F: StorageFile>(file: &F, version: FormatVersion, from: u64, len: u64) -> Result<Option<u64>> {
+    let defaults = SizeLimits::default();
+    for offset in from..len {
+        let remaining = len - offset;
+        let limits = SizeLimits {
+            max_key_size: defaults.max_key_size.min(remaining),
+            max_value_size: defaults.max_value_size.min(remaining),
+        };
+        match DataEntry::decode_with_limits(&mut StorageReader::new(file, offset), version, &limits) {
+            Ok(entry) if entry.check_crc() => return Ok(Some(offset)),
+            Err(EdgeKvError::Io(e)) if e.kind() != io::ErrorKind::UnexpectedEof => return Err(e.into()),
+            _ => {}
+        }
+    }
+    Ok(None)
+}
+
+/// Checks and repairs every data file in `storage`, and the hint file
+/// next to each, returning what was found and changed. For each data file:
+///
+/// * a torn final record is truncated;
+/// * if records failed their crc, the file is rewritten without them;
+/// * the hint file is rebuilt if it is missing, unreadable or does not
+///   match the intact records.
+///
+/// A file with a record that cannot be decoded before its end is only
+/// reported in `corrupt_records`: the records after it can't be located,
+/// and its hint file may be all that still points at them, so neither
+/// file is touched. Replacements are written under a temporary name,
+/// synced and renamed into place, so a crash mid-repair leaves the
+/// previous file. With `dry_run` nothing is written.
+///
+/// ```
+/// use edgekv::storage::MemStorage;
+///
+/// let report = edgekv::doctor(&MemStorage::new(), true).unwrap();
+/// assert!(report.is_clean() && report.dry_run);
+/// ```
+pub fn doctor<S: Storage>(storage: &S, dry_run: bool) -> Result<RecoveryReport> {
+    let mut report = RecoveryReport {
+        dry_run,
+        ..RecoveryReport::default()
+    };
+    for name in storage.list()?.iter().filter(|name| name.ends_with(DATA_SUFFIX)) {
+        repair_data_file(storage, name, &hint_file_name(name), dry_run, &mut report)?;
+    }
+    Ok(report)
+}
+
+/// Repairs one data file and its hint file, as described for [`doctor`].
+pub(crate) fn repair_data_file<S: Storage>(
+    storage: &S,
+    data_name: &str,
+    hint_name: &str,
+    dry_run: bool,
+    report: &mut RecoveryReport,
+) -> Result<()> {
+    let skipped_before = report.records_skipped;
+    let corrupt_before = report.corrupt_records.len();
+    let mut data = storage.open(data_name)?;
+    let mut hints = recover_data_file(data_name, &mut data, dry_run, report)?;
+    if report.corrupt_records.len() > corrupt_before {
+        report.records_skipped = skipped_before;
+        return Ok(());
+    }
+    let rewrite = report.records_skipped > skipped_before;
+    if rewrite && !report.files_repaired.iter().any(|name| name == data_name) {
+        report.files_repaired.push(data_name.to_owned());
+    }
+    if rewrite && !dry_run {
+        hints = replace_file(storage, data_name, |tmp| {
+            tmp.append(&FileHeader::new(FileKind::Data).encode())?;
+            let mut hints = vec![];
+            for record in Records::new(BufReader::new(StorageReader::new(&data, 0)))? {
+                let (_, entry) = record?;
+                if entry.check_crc() {
+                    let position = tmp.append(&entry.encode_version(FormatVersion::CURRENT))?;
+                    hints.push(HintEntry::from(&entry, position));
+                }
+            }
+            Ok(hints)
+        })?;
+    }
+
+    let existing = if storage.list()?.iter().any(|name| name == hint_name) {
+        let hint = storage.open(hint_name)?;
+        match read_hint_file(&mut BufReader::new(StorageReader::new(&hint, 0))) {
+            Ok(existing) => Some(existing),
+            Err(EdgeKvError::Io(e)) if e.kind() != io::ErrorKind::UnexpectedEof => return Err(e.into()),
+            Err(_) => None,
+        }
+    } else {
+        None
+    };
+    if existing.as_ref() == Some(&hints) && !rewrite {
+        return Ok(());
+    }
+    report.files_repaired.push(hint_name.to_owned());
+    if dry_run {
+        return Ok(());
+    }
+    replace_file(storage, hint_name, |tmp| {
+        let mut buf = vec![];
+        write_hint_file(&mut buf, &hints, false)?;
+        tmp.append(&buf)?;
+        Ok(())
+    })
+}
+
+/// Writes `name` afresh through `write` under its temporary name, syncs
+/// it and renames it over the original.
+fn replace_file<S: Storage, T, W: FnOnce(&mut S::File) -> Result<T>>(storage: &S, name: &str, write: W) -> Result<T> {
+    let tmp_name = temp_name(name);
+    if storage.list()?.contains(&tmp_name) {
+        storage.remove(&tmp_name)?;
+    }
+    let mut tmp = storage.open(&tmp_name)?;
+    let out = write(&mut tmp)?;
+    tmp.sync()?;
+    storage.rename(&tmp_name, name)?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::recovery::{doctor, recover_data_file, repair_data_file, RecoveryReport};
+    use crate::schema::{write_hint_file, HintEntry};
+    use crate::verify::verify;
+    use crate::schema::{read_hint_file, DataEntry, Decoder, Encoder, FileHeader, FileKind};
+    use crate::storage::StorageReader;
+    use std::io::BufReader;
+    use crate::storage::{MemStorage, Storage, StorageFile};
+
+    #[test]
+    fn recover_torn_tail_test() {
+        let storage = MemStorage::new();
+        let mut file = storage.open("000001.data").unwrap();
+        file.append(&FileHeader::new(FileKind::Data).encode()).unwrap();
+        for i in 0..3_u8 {
+            file.append(&DataEntry::new(0, vec![i], vec![i; 8]).encode()).unwrap();
+        }
+
+        let mut report = RecoveryReport::default();
+        let hints = recover_data_file("000001.data", &mut file, false, &mut report).unwrap();
+        assert_eq!(hints.len(), 3);
+        assert!(report.is_clean());
+
+        let mut corrupt = DataEntry::new(0, b"bad".to_vec(), b"crc".to_vec()).encode();
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 0xff;
+        file.append(&corrupt).unwrap();
+        file.append(&DataEntry::new(0, b"ok".to_vec(), b"v".to_vec()).encode()).unwrap();
+        let clean_len = file.len().unwrap();
+        let torn = DataEntry::new(0, b"torn".to_vec(), vec![7; 64]).encode();
+        file.append(&torn[..20]).unwrap();
+
+        let mut report = RecoveryReport::default();
+        recover_data_file("000001.data", &mut file, true, &mut report).unwrap();
+        assert_eq!(report.bytes_truncated, 20);
+        assert!(report.dry_run);
+        assert_eq!(file.len().unwrap(), clean_len + 20);
+
+        let mut report = RecoveryReport::default();
+        let hints = recover_data_file("000001.data", &mut file, false, &mut report).unwrap();
+        assert_eq!(hints.len(), 4);
+        assert_eq!(hints[3].key(), b"ok");
+        assert_eq!(report.records_skipped, 1);
+        assert_eq!(report.bytes_truncated, 20);
+        assert_eq!(report.files_repaired, vec!["000001.data"]);
+        assert_eq!(file.len().unwrap(), clean_len);
+
+        let mut wrong_kind = storage.open("000002.data").unwrap();
+        wrong_kind.append(&FileHeader::new(FileKind::Hint).encode()).unwrap();
+        let err = recover_data_file("000002.data", &mut wrong_kind, false, &mut report).unwrap_err();
+        assert!(err.to_string().starts_with("corruption in 000002.data:"));
+    }
+
+    #[test]
+    fn recover_stops_at_mid_file_corruption_test() {
+        let storage = MemStorage::new();
+        let mut file = storage.open("000001.data").unwrap();
+        file.append(&FileHeader::new(FileKind::Data).encode()).unwrap();
+        file.append(&DataEntry::new(0, b"first".to_vec(), b"1".to_vec()).encode()).unwrap();
+        let mut bad = DataEntry::new(0, b"bad".to_vec(), b"flags".to_vec()).encode();
+        bad[4] = 0xf0;
+        let bad_at = file.append(&bad).unwrap();
+        for i in 0..100_u8 {
+            file.append(&DataEntry::new(0, vec![i], vec![i; 8]).encode()).unwrap();
+        }
+        let len = file.len().unwrap();
+
+        let mut report = RecoveryReport::default();
+        let hints = recover_data_file("000001.data", &mut file, false, &mut report).unwrap();
+        assert_eq!(hints.len(), 1);
+        assert_eq!(report.bytes_truncated, 0);
+        assert_eq!(report.corrupt_records.len(), 1);
+        assert_eq!(report.corrupt_records[0].offset, bad_at);
+        assert!(report.files_repaired.is_empty());
+        assert!(!report.is_clean());
+        assert_eq!(file.len().unwrap(), len);
+    }
+
+    #[test]
+    fn recover_stops_at_bad_size_mid_file_test() {
+        let storage = MemStorage::new();
+        let mut file = storage.open("000001.data").unwrap();
+        file.append(&FileHeader::new(FileKind::Data).encode()).unwrap();
+        // Setting the continuation bit pulls the key byte into the value
+        // size, which then claims about 16 KiB: within the limits, but past
+        // the end of the file.
+        let mut bad = DataEntry::new(0, vec![0x7f], b"v".to_vec()).encode();
+        let value_size_at = bad.len() - 3;
+        bad[value_size_at] |= 0x80;
+        let bad_at = file.append(&bad).unwrap();
+        for i in 0..100_u8 {
+            file.append(&DataEntry::new(0, vec![i], vec![i; 8]).encode()).unwrap();
+        }
+        let len = file.len().unwrap();
+
+        let mut report = RecoveryReport::default();
+        let hints = recover_data_file("000001.data", &mut file, false, &mut report).unwrap();
+        assert!(hints.is_empty());
+        assert_eq!(report.bytes_truncated, 0);
+        assert_eq!(report.corrupt_records.len(), 1);
+        assert_eq!(report.corrupt_records[0].offset, bad_at);
+        assert!(report.files_repaired.is_empty());
+        assert_eq!(file.len().unwrap(), len);
+    }
+
+    #[test]
+    fn repair_rebuilds_hint_file_test() {
+        let storage = MemStorage::new();
+        let mut data = storage.open("000001.data").unwrap();
+        data.append(&FileHeader::new(FileKind::Data).encode()).unwrap();
+        data.append(&DataEntry::new(0, b"a".to_vec(), b"1".to_vec()).encode()).unwrap();
+        data.append(&DataEntry::new(0, b"torn".to_vec(), b"2".to_vec()).encode()[..5]).unwrap();
+        storage.open("000001.hint").unwrap().append(b"garbage").unwrap();
+
+        let mut report = RecoveryReport::default();
+        repair_data_file(&storage, "000001.data", "000001.hint", true, &mut report).unwrap();
+        assert_eq!(report.files_repaired, vec!["000001.data", "000001.hint"]);
+        assert_eq!(storage.open("000001.hint").unwrap().len().unwrap(), 7);
+
+        let mut report = RecoveryReport::default();
+        repair_data_file(&storage, "000001.data", "000001.hint", false, &mut report).unwrap();
+        assert_eq!(report.bytes_truncated, 5);
+        assert_eq!(storage.list().unwrap(), vec!["000001.data", "000001.hint"]);
+        let hint = storage.open("000001.hint").unwrap();
+        let hints = read_hint_file(&mut BufReader::new(StorageReader::new(&hint, 0))).unwrap();
+        assert_eq!(hints.len(), 1);
+        assert_eq!(hints[0].key(), b"a");
+    }
+
+    fn contents(storage: &MemStorage) -> Vec<(String, Vec<u8>)> {
+        let names = storage.list().unwrap();
+        names
+            .into_iter()
+            .map(|name| {
+                let file = storage.open(&name).unwrap();
+                let mut buf = vec![0_u8; file.len().unwrap() as usize];
+                file.read_at(0, &mut buf).unwrap();
+                (name, buf)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn doctor_test() {
+        let storage = MemStorage::new();
+        // 000001: a crc failure mid-file, a torn tail and a stale hint file.
+        let mut first = storage.open("000001.data").unwrap();
+        first.append(&FileHeader::new(FileKind::Data).encode()).unwrap();
+        first.append(&DataEntry::new(0, b"a".to_vec(), b"1".to_vec()).encode()).unwrap();
+        let mut bad_crc = DataEntry::new(0, b"b".to_vec(), b"2".to_vec()).encode();
+        let last = bad_crc.len() - 1;
+        bad_crc[last] ^= 0xff;
+        first.append(&bad_crc).unwrap();
+        first.append(&DataEntry::new(0, b"c".to_vec(), b"3".to_vec()).encode()).unwrap();
+        first.append(&DataEntry::new(0, b"torn".to_vec(), b"4".to_vec()).encode()[..6]).unwrap();
+        storage.open("000001.hint").unwrap().append(b"stale").unwrap();
+
+        // 000002: clean, with a matching hint file.
+        let mut second = storage.open("000002.data").unwrap();
+        second.append(&FileHeader::new(FileKind::Data).encode()).unwrap();
+        let entry = DataEntry::new(0, b"d".to_vec(), b"5".to_vec());
+        let hint = HintEntry::from(&entry, second.append(&entry.encode()).unwrap());
+        let mut hint_buf = vec![];
+        write_hint_file(&mut hint_buf, &[hint], false).unwrap();
+        storage.open("000002.hint").unwrap().append(&hint_buf).unwrap();
+
+        // 000003: a crc failure, then an undecodable record with good data
+        // after it.
+        let mut third = storage.open("000003.data").unwrap();
+        third.append(&FileHeader::new(FileKind::Data).encode()).unwrap();
+        third.append(&bad_crc).unwrap();
+        let mut bad_flags = DataEntry::new(0, b"e".to_vec(), b"6".to_vec()).encode();
+        bad_flags[4] = 0xf0;
+        third.append(&bad_flags).unwrap();
+        third.append(&DataEntry::new(0, b"f".to_vec(), b"7".to_vec()).encode()).unwrap();
+
+        let before = contents(&storage);
+        let report = doctor(&storage, true).unwrap();
+        assert!(report.dry_run);
+        assert_eq!(report.records_skipped, 1);
+        assert_eq!(report.bytes_truncated, 6);
+        assert_eq!(report.corrupt_records.len(), 1);
+        assert_eq!(report.files_repaired, vec!["000001.data", "000001.hint"]);
+        assert_eq!(contents(&storage), before);
+
+        let report = doctor(&storage, false).unwrap();
+        assert_eq!(report.files_repaired, vec!["000001.data", "000001.hint"]);
+        assert_eq!(report.corrupt_records[0].file, "000003.data");
+        let checked = verify(&storage).unwrap();
+        assert_eq!(checked.corrupt_records.len(), 2);
+        assert!(checked.corrupt_records.iter().all(|r| r.file == "000003.data"));
+        assert!(checked.orphaned_hints.is_empty());
+        assert_eq!(contents(&storage)[2..], before[2..]);
+
+        let data = storage.open("000001.data").unwrap();
+        let hint = storage.open("000001.hint").unwrap();
+        let hints = read_hint_file(&mut BufReader::new(StorageReader::new(&hint, 0))).unwrap();
+        let keys: Vec<&[u8]> = hints.iter().map(|h| h.key()).collect();
+        assert_eq!(keys, vec![b"a", b"c"]);
+        let mut rdr = StorageReader::new(&data, hints[1].data_entry_position());
+        assert_eq!(DataEntry::decode(&mut rdr).unwrap().value(), b"3");
+
+        let again = doctor(&storage, false).unwrap();
+        assert!(again.files_repaired.is_empty());
+        assert_eq!(again.corrupt_records.len(), 1);
+    }
+}
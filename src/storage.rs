@@ -0,0 +1,429 @@
+//! File access behind a small trait, so data and hint files can live on
+//! something other than a local directory: an in-memory map for tests, an
+//! encrypted container, or an object-store-backed archive.
+//!
+//! [`FsStorage`] is the default `std::fs` implementation and
+//! [`MemStorage`] keeps everything in memory.
+
+use crate::error::Result;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+/// A flat namespace of append-only files.
+///
+/// Durability: once `open` has created a file, or `rename` or `remove`
+/// has returned, that change to the namespace survives power loss. File
+/// contents are only durable after [`StorageFile::sync`].
+pub trait Storage {
+    type File: StorageFile;
+
+    /// Opens `name`, creating it empty if it does not exist yet.
+    fn open(&self, name: &str) -> Result<Self::File>;
+
+    /// Names of all files, sorted.
+    fn list(&self) -> Result<Vec<String>>;
+
+    /// Atomically replaces `to` with `from`.
+    fn rename(&self, from: &str, to: &str) -> Result<()>;
+
+    fn remove(&self, name: &str) -> Result<()>;
+
+    /// Makes the current set of file names durable.
+    fn sync_dir(&self) -> Result<()>;
+}
+
+/// An append-only file with positional reads.
+pub trait StorageFile {
+    /// Appends `buf` and returns the offset it was written at.
+    fn append(&mut self, buf: &[u8]) -> Result<u64>;
+
+    /// Fills `buf` from `offset`, failing if the file ends first.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<()>;
+
+    fn len(&self) -> Result<u64>;
+
+    fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Cuts the file down to `len` bytes, e.g. to drop a torn tail.
+    fn truncate(&mut self, len: u64) -> Result<()>;
+
+    /// Makes everything appended so far durable.
+    fn sync(&self) -> Result<()>;
+}
+
+/// Files in a local directory.
+#[derive(Debug, Clone)]
+pub struct FsStorage {
+    dir: PathBuf,
+}
+
+impl FsStorage {
+    /// Uses `dir` as the storage root, creating it if needed.
+    pub fn new<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        fs::create_dir_all(dir.as_ref())?;
+        Ok(Self {
+            dir: dir.as_ref().to_path_buf(),
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+}
+
+impl Storage for FsStorage {
+    type File = FsFile;
+
+    fn open(&self, name: &str) -> Result<FsFile> {
+        let path = self.dir.join(name);
+        let mut options = OpenOptions::new();
+        options.read(true).append(true);
+        let file = match options.clone().create_new(true).open(&path) {
+            Ok(file) => {
+                self.sync_dir()?;
+                file
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => options.open(&path)?,
+            Err(e) => return Err(e.into()),
+        };
+        let len = file.metadata()?.len();
+        Ok(FsFile {
+            file,
+            len,
+            #[cfg(not(any(unix, windows)))]
+            read_lock: Mutex::new(()),
+        })
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        let mut names = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                if let Some(name) = entry.file_name().to_str() {
+                    names.push(name.to_owned());
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        fs::rename(self.dir.join(from), self.dir.join(to))?;
+        self.sync_dir()
+    }
+
+    fn remove(&self, name: &str) -> Result<()> {
+        fs::remove_file(self.dir.join(name))?;
+        self.sync_dir()
+    }
+
+    /// Directory entries only reach disk once the directory itself is
+    /// fsynced; on ext4 a crash right after creating a file can otherwise
+    /// lose it entirely.
+    #[cfg(unix)]
+    fn sync_dir(&self) -> Result<()> {
+        File::open(&self.dir)?.sync_all()?;
+        Ok(())
+    }
+
+    /// Windows persists directory entries with the file metadata and does
+    /// not allow opening a directory as a file.
+    #[cfg(not(unix))]
+    fn sync_dir(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct FsFile {
+    file: File,
+    len: u64,
+    /// Without positional reads, a read is a seek plus a read, and the two
+    /// must not interleave with another reader's.
+    #[cfg(not(any(unix, windows)))]
+    read_lock: Mutex<()>,
+}
+
+impl StorageFile for FsFile {
+    /// A write that fails part way is cut back off, so the cached length
+    /// and the offsets of later appends stay right. If even that fails the
+    /// length is taken from the file as it now is.
+    fn append(&mut self, buf: &[u8]) -> Result<u64> {
+        let offset = self.len;
+        if let Err(e) = self.file.write_all(buf) {
+            if self.file.set_len(offset).is_err() {
+                self.len = self.file.metadata()?.len();
+            }
+            return Err(e.into());
+        }
+        self.len += buf.len() as u64;
+        Ok(offset)
+    }
+
+    #[cfg(unix)]
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        use std::os::unix::fs::FileExt;
+        self.file.read_exact_at(buf, offset)?;
+        Ok(())
+    }
+
+    #[cfg(windows)]
+    fn read_at(&self, mut offset: u64, mut buf: &mut [u8]) -> Result<()> {
+        use std::os::windows::fs::FileExt;
+        while !buf.is_empty() {
+            match self.file.seek_read(buf, offset)? {
+                0 => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                n => {
+                    buf = &mut buf[n..];
+                    offset += n as u64;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let _guard = self.read_lock.lock().unwrap();
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(buf)?;
+        Ok(())
+    }
+
+    fn len(&self) -> Result<u64> {
+        Ok(self.len)
+    }
+
+    /// Compares against the length on disk rather than the cached one, so
+    /// bytes a failed write left behind are still dropped.
+    fn truncate(&mut self, len: u64) -> Result<()> {
+        let actual = self.file.metadata()?.len();
+        if len < actual {
+            self.file.set_len(len)?;
+        }
+        self.len = actual.min(len);
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.file.sync_data()?;
+        Ok(())
+    }
+}
+
+type MemBuffer = Arc<RwLock<Vec<u8>>>;
+
+/// Same error `FsStorage` gets from the OS for a missing file.
+fn no_such_file(name: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("no such file: {}", name))
+}
+
+/// Files held in memory. Clones share the same files.
+#[derive(Debug, Clone, Default)]
+pub struct MemStorage {
+    files: Arc<Mutex<BTreeMap<String, MemBuffer>>>,
+}
+
+impl MemStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Storage for MemStorage {
+    type File = MemFile;
+
+    fn open(&self, name: &str) -> Result<MemFile> {
+        let mut files = self.files.lock().unwrap();
+        let data = files.entry(name.to_owned()).or_default().clone();
+        Ok(MemFile { data })
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        Ok(self.files.lock().unwrap().keys().cloned().collect())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let mut files = self.files.lock().unwrap();
+        match files.remove(from) {
+            Some(data) => {
+                files.insert(to.to_owned(), data);
+                Ok(())
+            }
+            None => Err(no_such_file(from).into()),
+        }
+    }
+
+    fn remove(&self, name: &str) -> Result<()> {
+        if self.files.lock().unwrap().remove(name).is_none() {
+            return Err(no_such_file(name).into());
+        }
+        Ok(())
+    }
+
+    fn sync_dir(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MemFile {
+    data: MemBuffer,
+}
+
+impl StorageFile for MemFile {
+    fn append(&mut self, buf: &[u8]) -> Result<u64> {
+        let mut data = self.data.write().unwrap();
+        let offset = data.len() as u64;
+        data.extend_from_slice(buf);
+        Ok(offset)
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let data = self.data.read().unwrap();
+        let start = offset as usize;
+        let end = start.saturating_add(buf.len());
+        match data.get(start..end) {
+            Some(src) => buf.copy_from_slice(src),
+            None => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+        }
+        Ok(())
+    }
+
+    fn len(&self) -> Result<u64> {
+        Ok(self.data.read().unwrap().len() as u64)
+    }
+
+    fn truncate(&mut self, len: u64) -> Result<()> {
+        self.data.write().unwrap().truncate(len as usize);
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Sequential `Read + Seek` view over a [`StorageFile`], so the record
+/// decoders can run against any backend. Wrap it in a `BufReader` for
+/// anything but single reads.
+pub struct StorageReader<'a, F: StorageFile> {
+    file: &'a F,
+    position: u64,
+}
+
+impl<'a, F: StorageFile> StorageReader<'a, F> {
+    pub fn new(file: &'a F, position: u64) -> Self {
+        Self { file, position }
+    }
+}
+
+impl<'a, F: StorageFile> Read for StorageReader<'a, F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.file.len()?;
+        let n = len.saturating_sub(self.position).min(buf.len() as u64) as usize;
+        self.file
+            .read_at(self.position, &mut buf[..n])?;
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl<'a, F: StorageFile> Seek for StorageReader<'a, F> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let len = self.file.len()?;
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => len.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+        };
+        match target {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "seek before start of file")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::{Storage, StorageFile, StorageReader, FsStorage, MemStorage};
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    fn exercise<S: Storage>(storage: S) {
+        let mut file = storage.open("000001.data").unwrap();
+        assert!(file.is_empty().unwrap());
+        assert_eq!(file.append(b"hello ").unwrap(), 0);
+        assert_eq!(file.append(b"world").unwrap(), 6);
+        file.sync().unwrap();
+
+        let mut buf = [0_u8; 5];
+        file.read_at(6, &mut buf).unwrap();
+        assert_eq!(&buf, b"world");
+        assert!(file.read_at(8, &mut buf).is_err());
+
+        file.append(b"!!").unwrap();
+        file.truncate(11).unwrap();
+        assert_eq!(file.len().unwrap(), 11);
+
+        let reopened = storage.open("000001.data").unwrap();
+        assert_eq!(reopened.len().unwrap(), 11);
+        let mut rdr = StorageReader::new(&reopened, 0);
+        rdr.seek(SeekFrom::Start(6)).unwrap();
+        let mut rest = String::new();
+        rdr.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "world");
+
+        storage.open("000000.hint").unwrap();
+        assert_eq!(storage.list().unwrap(), vec!["000000.hint", "000001.data"]);
+        storage.remove("000000.hint").unwrap();
+        assert_eq!(storage.list().unwrap(), vec!["000001.data"]);
+
+        storage.open("000002.data.tmp").unwrap().append(b"merged").unwrap();
+        storage.rename("000002.data.tmp", "000001.data").unwrap();
+        storage.sync_dir().unwrap();
+        assert_eq!(storage.list().unwrap(), vec!["000001.data"]);
+        assert_eq!(storage.open("000001.data").unwrap().len().unwrap(), 6);
+        assert!(storage.rename("missing", "000001.data").is_err());
+    }
+
+    #[test]
+    fn mem_storage_test() {
+        exercise(MemStorage::new());
+    }
+
+    #[test]
+    fn fs_storage_test() {
+        let dir = std::env::temp_dir().join(format!("edgekv-storage-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        exercise(FsStorage::new(&dir).unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fs_file_truncate_test() {
+        let dir = std::env::temp_dir().join(format!("edgekv-truncate-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let storage = FsStorage::new(&dir).unwrap();
+        let mut file = storage.open("000001.data").unwrap();
+        file.append(b"record").unwrap();
+
+        // Bytes that reached the file without the cached length moving, as
+        // after a write that failed part way.
+        file.file.write_all(b"partial").unwrap();
+        file.truncate(6).unwrap();
+        assert_eq!(file.append(b"next").unwrap(), 6);
+        assert_eq!(storage.open("000001.data").unwrap().len().unwrap(), 10);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
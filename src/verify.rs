@@ -0,0 +1,190 @@
+//! Read-only integrity checking of data and hint files.
+
+use crate::error::{EdgeKvError, Result};
+use crate::merge::{hint_file_name, DATA_SUFFIX};
+use crate::schema::{read_hint_file, HintEntry, Records};
+use crate::storage::{Storage, StorageFile, StorageReader};
+use std::collections::HashMap;
+use std::io::{self, BufReader};
+
+/// Everything wrong that verification found. Nothing is modified on disk.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VerifyReport {
+    pub files_checked: u64,
+    pub records_checked: u64,
+    pub corrupt_records: Vec<CorruptRecord>,
+    pub orphaned_hints: Vec<OrphanedHint>,
+}
+
+impl VerifyReport {
+    pub fn is_clean(&self) -> bool {
+        self.corrupt_records.is_empty() && self.orphaned_hints.is_empty()
+    }
+}
+
+/// A data file record that failed its crc or could not be decoded. After
+/// an undecodable record the rest of the file cannot be located, so it is
+/// the last one reported for that file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CorruptRecord {
+    pub file: String,
+    pub offset: u64,
+    pub reason: String,
+}
+
+impl CorruptRecord {
+    /// Records a decode failure. The offset is kept in its own field, so
+    /// only the bare reason of a corruption error is kept as the reason.
+    pub(crate) fn from_error(file: &str, offset: u64, e: EdgeKvError) -> Self {
+        let reason = match e {
+            EdgeKvError::Corruption { reason, .. } => reason,
+            e => e.to_string(),
+        };
+        Self {
+            file: file.to_owned(),
+            offset,
+            reason,
+        }
+    }
+}
+
+/// A hint entry that does not match an intact record in its data file, or
+/// a hint file that could not be read at all (`key` empty).
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrphanedHint {
+    pub file: String,
+    pub data_offset: u64,
+    pub key: Vec<u8>,
+    pub reason: String,
+}
+
+/// Checks every data file in `storage`, and the hint file next to each,
+/// without changing anything.
+///
+/// ```
+/// use edgekv::storage::MemStorage;
+///
+/// let report = edgekv::verify(&MemStorage::new()).unwrap();
+/// assert!(report.is_clean());
+/// ```
+pub fn verify<S: Storage>(storage: &S) -> Result<VerifyReport> {
+    let names = storage.list()?;
+    let mut report = VerifyReport::default();
+    for data_name in names.iter().filter(|name| name.ends_with(DATA_SUFFIX)) {
+        let data = storage.open(data_name)?;
+        let hint_name = hint_file_name(data_name);
+        if names.contains(&hint_name) {
+            let hint = storage.open(&hint_name)?;
+            verify_data_file(data_name, &data, Some((&hint_name, &hint)), &mut report)?;
+        } else {
+            verify_data_file(data_name, &data, None, &mut report)?;
+        }
+    }
+    Ok(report)
+}
+
+/// Streams every record of the data file, checking its crc, then checks
+/// each entry of its hint file, if any, against the records found.
+pub(crate) fn verify_data_file<F: StorageFile>(
+    data_name: &str,
+    data: &F,
+    hint: Option<(&str, &F)>,
+    report: &mut VerifyReport,
+) -> Result<()> {
+    report.files_checked += 1;
+    let mut intact = HashMap::new();
+    let mut records = Records::new(BufReader::new(StorageReader::new(data, 0))).map_err(|e| e.in_file(data_name))?;
+    while let Some(record) = records.next() {
+        report.records_checked += 1;
+        match record {
+            Ok((offset, entry)) if entry.check_crc() => {
+                intact.insert(offset, HintEntry::from(&entry, offset));
+            }
+            Ok((offset, _)) => report.corrupt_records.push(CorruptRecord {
+                file: data_name.to_owned(),
+                offset,
+                reason: "checksum mismatch".to_owned(),
+            }),
+            Err(EdgeKvError::Io(e)) if e.kind() != io::ErrorKind::UnexpectedEof => return Err(e.into()),
+            Err(e) => report.corrupt_records.push(CorruptRecord::from_error(data_name, records.position(), e)),
+        }
+    }
+
+    if let Some((hint_name, hint)) = hint {
+        report.files_checked += 1;
+        let hints = match read_hint_file(&mut BufReader::new(StorageReader::new(hint, 0))) {
+            Ok(hints) => hints,
+            Err(EdgeKvError::Io(e)) if e.kind() != io::ErrorKind::UnexpectedEof => return Err(e.into()),
+            Err(e) => {
+                report.orphaned_hints.push(OrphanedHint {
+                    file: hint_name.to_owned(),
+                    data_offset: 0,
+                    key: vec![],
+                    reason: e.to_string(),
+                });
+                return Ok(());
+            }
+        };
+        for hint in hints.into_iter().filter(|h| !h.is_deleted()) {
+            let reason = match intact.get(&hint.data_entry_position()) {
+                None => "no intact record at offset",
+                Some(record) if *record != hint => "hint does not match record",
+                Some(_) => continue,
+            };
+            report.orphaned_hints.push(OrphanedHint {
+                file: hint_name.to_owned(),
+                data_offset: hint.data_entry_position(),
+                key: hint.into_key(),
+                reason: reason.to_owned(),
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schema::{write_hint_file, DataEntry, Encoder, FileHeader, FileKind, HintEntry};
+    use crate::storage::{MemStorage, Storage, StorageFile};
+    use crate::verify::{verify, verify_data_file, VerifyReport};
+
+    #[test]
+    fn verify_test() {
+        let storage = MemStorage::new();
+        let mut data = storage.open("000001.data").unwrap();
+        data.append(&FileHeader::new(FileKind::Data).encode()).unwrap();
+        let mut hints = vec![];
+        for i in 0..3_u8 {
+            let entry = DataEntry::new(0, vec![i], vec![i; 8]);
+            let mut encoded = entry.encode();
+            if i == 1 {
+                let last = encoded.len() - 1;
+                encoded[last] ^= 0xff;
+            }
+            hints.push(HintEntry::from(&entry, data.append(&encoded).unwrap()));
+        }
+        hints.push(HintEntry::from(&DataEntry::new(0, vec![9], vec![]), 4096));
+        hints.push(HintEntry::tombstone(vec![7]));
+
+        let mut hint_buf = vec![];
+        write_hint_file(&mut hint_buf, &hints, false).unwrap();
+        let mut hint = storage.open("000001.hint").unwrap();
+        hint.append(&hint_buf).unwrap();
+
+        let mut report = VerifyReport::default();
+        verify_data_file("000001.data", &data, Some(("000001.hint", &hint)), &mut report).unwrap();
+        assert_eq!(report.files_checked, 2);
+        assert_eq!(report.records_checked, 3);
+        assert_eq!(report.corrupt_records.len(), 1);
+        assert_eq!(report.corrupt_records[0].offset, hints[1].data_entry_position());
+        let orphaned: Vec<u64> = report.orphaned_hints.iter().map(|h| h.data_offset).collect();
+        assert_eq!(orphaned, vec![hints[1].data_entry_position(), 4096]);
+        assert_eq!(verify(&storage).unwrap(), report);
+
+        data.append(&[0xff; 3]).unwrap();
+        let mut report = VerifyReport::default();
+        verify_data_file("000001.data", &data, None, &mut report).unwrap();
+        assert_eq!(report.corrupt_records.len(), 2);
+        assert!(!report.is_clean());
+    }
+}